@@ -1,11 +1,11 @@
 //! Router context for external calls mocks.
 
 use std::{
-    borrow::BorrowMut, marker::PhantomData, sync::LazyLock, thread::ThreadId,
+    borrow::BorrowMut, cell::RefCell, collections::HashMap,
+    marker::PhantomData, rc::Rc,
 };
 
 use alloy_primitives::Address;
-use dashmap::{mapref::one::RefMut, DashMap};
 use stylus_sdk::{
     abi::router_entrypoint,
     host::{WasmVM, VM},
@@ -13,93 +13,248 @@ use stylus_sdk::{
     ArbResult,
 };
 
-use crate::{
-    context::create_default_storage_type, storage_access::AccessStorage,
-};
+use crate::context::create_default_storage_type;
 
-/// Motsu VM Router Storage.
-///
-/// A global mutable key-value store that allows concurrent access.
-///
-/// The key is the [`VMRouter`], a combination of [`ThreadId`] and
-/// [`Address`] to avoid a panic on lock, while calling more than two contracts
-/// consecutive.
-///
-/// The value is the [`VMRouterStorage`], a router of the contract generated by
-/// `stylus-sdk`.
+/// The call depth limit enforced by the real Arbitrum/EVM runtime, past
+/// which a call reverts rather than recursing further.
+const EVM_MAX_CALL_DEPTH: usize = 1024;
+
+thread_local! {
+    /// Motsu VM Router Storage.
+    ///
+    /// A thread-local mutable key-value store, isolating router state
+    /// between concurrently running test threads.
+    ///
+    /// The key is the contract's [`Address`].
+    ///
+    /// The value is the [`VMRouterStorage`], a router of the contract
+    /// generated by `stylus-sdk`.
+    static MOTSU_VM_ROUTERS: RefCell<HashMap<Address, VMRouterStorage>> =
+        RefCell::new(HashMap::new());
+
+    /// Stack of contract addresses currently being called on this thread,
+    /// innermost call last.
+    static CALL_STACK: RefCell<Vec<Address>> = const { RefCell::new(Vec::new()) };
+
+    /// Maximum depth [`CALL_STACK`] is allowed to reach before `route`
+    /// refuses to recurse further, mirroring the EVM's call depth limit.
+    static MAX_CALL_DEPTH: RefCell<usize> = const { RefCell::new(EVM_MAX_CALL_DEPTH) };
+}
+
+/// RAII guard that pushes `contract_address` onto [`CALL_STACK`] on
+/// creation and pops it on drop, so the stack unwinds correctly even when
+/// `route` panics or reverts.
+struct CallStackGuard;
+
+impl CallStackGuard {
+    fn new(contract_address: Address) -> Self {
+        CALL_STACK.with_borrow_mut(|stack| stack.push(contract_address));
+        Self
+    }
+}
+
+impl Drop for CallStackGuard {
+    fn drop(&mut self) {
+        CALL_STACK.with_borrow_mut(|stack| {
+            stack.pop();
+        });
+    }
+}
+
+/// Context of Motsu test VM router associated with a contract's address.
 ///
-/// NOTE: The [`VMRouter::storage`] will panic on lock, when the same key
-/// is accessed twice from the same thread.
-static MOTSU_VM_ROUTERS: LazyLock<DashMap<VMRouter, VMRouterStorage>> =
-    LazyLock::new(DashMap::new);
-
-/// Context of Motsu test VM router associated with the current test thread and
-/// contract's address.
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
-pub(crate) struct VMRouter {
-    thread_id: ThreadId,
+/// Besides the crate-internal dispatch machinery, this is also the handle
+/// test authors use to register call stubs and inspect/bound the mocked
+/// call stack, so it is part of the crate's public test surface.
+#[derive(Copy, Clone)]
+pub struct VMRouter {
     contract_address: Address,
 }
 
 impl VMRouter {
-    /// Create a new router context.
-    pub(crate) fn new(thread: ThreadId, contract_address: Address) -> Self {
-        Self { thread_id: thread, contract_address }
+    /// Create a router context for `contract_address`.
+    pub fn new(contract_address: Address) -> Self {
+        Self { contract_address }
     }
 
-    /// Get reference to the call storage for the current test thread.
-    fn storage(self) -> RefMut<'static, VMRouter, VMRouterStorage> {
-        MOTSU_VM_ROUTERS.access_storage(&self)
-    }
-
-    /// Check if the router exists for the contract.
+    /// Check if the router exists for the contract, either as a full
+    /// [`Router`] implementation or as a stub registered via
+    /// [`VMRouter::mock_call`] / [`VMRouter::mock_revert`].
     pub(crate) fn exists(self) -> bool {
-        MOTSU_VM_ROUTERS.contains_key(&self)
+        MOTSU_VM_ROUTERS.with_borrow(|routers| {
+            routers.get(&self.contract_address).is_some_and(|storage| {
+                storage.router_factory.is_some() || !storage.stubs.is_empty()
+            })
+        })
     }
 
     pub(crate) fn route(self, calldata: Vec<u8>) -> ArbResult {
-        let storage = self.storage();
-        let mut router = storage.router_factory.create();
+        if Self::call_depth() >= Self::max_call_depth() {
+            return Err(format!(
+                "call depth exceeded the maximum of {} while calling contract at {}",
+                Self::max_call_depth(),
+                self.contract_address
+            )
+            .into_bytes());
+        }
+
+        let selector = selector_of(&calldata);
 
-        // Drop the storage reference to avoid a panic on lock.
-        drop(storage);
+        // A registered stub short-circuits the real router entirely, the
+        // same way a canned response never reaches a live contract's code.
+        // Clone the stub out and end the borrow before invoking it, so a
+        // stub calling back into another contract on the same thread
+        // doesn't trigger a `RefCell` double-borrow.
+        let stub = selector.and_then(|selector| {
+            MOTSU_VM_ROUTERS.with_borrow(|routers| {
+                routers
+                    .get(&self.contract_address)
+                    .and_then(|storage| storage.stubs.get(&selector))
+                    .map(Rc::clone)
+            })
+        });
 
+        if let Some(stub) = stub {
+            // Track this call on the thread-local call stack for the
+            // lifetime of the stub invocation, so the stack unwinds
+            // correctly on panic/revert.
+            let _guard = CallStackGuard::new(self.contract_address);
+            return stub(calldata);
+        }
+
+        // Clone the factory out and end the borrow before calling `create`/
+        // `route`, so a contract calling back into another contract on the
+        // same thread doesn't trigger a `RefCell` double-borrow.
+        let router_factory = MOTSU_VM_ROUTERS.with_borrow(|routers| {
+            routers
+                .get(&self.contract_address)
+                .and_then(|storage| storage.router_factory.clone())
+        });
+
+        let router_factory = router_factory.unwrap_or_else(|| {
+            panic!(
+                "contract's router is not initialized - contract_address is {}",
+                self.contract_address
+            )
+        });
+
+        // Track this call on the thread-local call stack for the
+        // lifetime of the inner `route` call, so the stack unwinds
+        // correctly on panic/revert.
+        let _guard = CallStackGuard::new(self.contract_address);
+
+        let mut router = router_factory.create();
         router.route(calldata)
     }
 
+    /// Registers a stub that short-circuits `route` for calls to this
+    /// contract whose calldata starts with the 4-byte `selector`, returning
+    /// `f`'s result instead of dispatching to a real [`Router`].
+    ///
+    /// Lets a test exercise a contract's interaction logic against an
+    /// interface it depends on without writing and deploying a full mock
+    /// implementation of that dependency.
+    pub fn mock_call(
+        self,
+        selector: [u8; 4],
+        f: impl Fn(Vec<u8>) -> ArbResult + 'static,
+    ) {
+        MOTSU_VM_ROUTERS.with_borrow_mut(|routers| {
+            routers
+                .entry(self.contract_address)
+                .or_default()
+                .stubs
+                .insert(selector, Rc::new(f));
+        });
+    }
+
+    /// Registers a stub that makes calls to this contract matching
+    /// `selector` simulate a revert with the given `data`, as if a real
+    /// contract had reverted.
+    pub fn mock_revert(self, selector: [u8; 4], data: Vec<u8>) {
+        self.mock_call(selector, move |_calldata| Err(data.clone()));
+    }
+
+    /// Returns a snapshot of the addresses of contracts currently being
+    /// called on this thread, ordered from outermost to innermost.
+    pub fn current_call_stack() -> Vec<Address> {
+        CALL_STACK.with_borrow(Clone::clone)
+    }
+
+    /// Returns the current depth of nested calls on this thread, i.e. the
+    /// number of contracts currently being called.
+    pub fn call_depth() -> usize {
+        CALL_STACK.with_borrow(Vec::len)
+    }
+
+    /// Sets the maximum call depth allowed on this thread before `route`
+    /// returns an error instead of recursing further.
+    ///
+    /// Defaults to [`EVM_MAX_CALL_DEPTH`], matching the real Arbitrum/EVM
+    /// limit.
+    pub fn set_max_call_depth(n: usize) {
+        MAX_CALL_DEPTH.with_borrow_mut(|max_depth| *max_depth = n);
+    }
+
+    /// Returns the maximum call depth currently configured for this
+    /// thread.
+    fn max_call_depth() -> usize {
+        MAX_CALL_DEPTH.with_borrow(|max_depth| *max_depth)
+    }
+
     /// Initialise contract router for the current test thread and
     /// `contract_address`.
     pub(crate) fn init_storage<ST: StorageType + Router + 'static>(self) {
         let contract_address = self.contract_address;
-        if MOTSU_VM_ROUTERS
-            .insert(
-                self,
-                VMRouterStorage {
-                    router_factory: Box::new(RouterFactory::<ST> {
-                        phantom: PhantomData,
-                    }),
-                },
-            )
-            .is_some()
-        {
-            panic!("contract's router is already initialized - contract_address is {contract_address}");
-        }
+        MOTSU_VM_ROUTERS.with_borrow_mut(|routers| {
+            let storage = routers.entry(contract_address).or_default();
+            if storage.router_factory.is_some() {
+                panic!("contract's router is already initialized - contract_address is {contract_address}");
+            }
+            storage.router_factory = Some(Rc::new(RouterFactory::<ST> {
+                phantom: PhantomData,
+            }));
+        });
     }
 
     /// Reset router storage for the current [`VMRouter`].
+    ///
+    /// Also resets the thread-wide call stack and max call depth, since
+    /// both are thread-local rather than per-contract state and must not
+    /// leak into the next test if this thread is reused.
     pub(crate) fn reset_storage(self) {
-        MOTSU_VM_ROUTERS.remove(&self);
+        MOTSU_VM_ROUTERS.with_borrow_mut(|routers| {
+            routers.remove(&self.contract_address);
+        });
+        CALL_STACK.with_borrow_mut(|stack| stack.clear());
+        MAX_CALL_DEPTH
+            .with_borrow_mut(|max_depth| *max_depth = EVM_MAX_CALL_DEPTH);
     }
 }
 
 /// Metadata related to the router of an external contract.
+#[derive(Default)]
 struct VMRouterStorage {
-    // Contract's router.
-    router_factory: Box<dyn CreateRouter>,
+    // Contract's router, if a full [`Router`] implementation was registered
+    // via [`VMRouter::init_storage`].
+    router_factory: Option<Rc<dyn CreateRouter>>,
+
+    // Canned responses keyed by the 4-byte selector prefix of the calldata
+    // they short-circuit, registered via [`VMRouter::mock_call`] /
+    // [`VMRouter::mock_revert`].
+    stubs: HashMap<[u8; 4], Rc<dyn Fn(Vec<u8>) -> ArbResult>>,
+}
+
+/// Extracts the 4-byte selector prefix from `calldata`, or `None` if it is
+/// too short to contain one.
+fn selector_of(calldata: &[u8]) -> Option<[u8; 4]> {
+    calldata
+        .get(..4)
+        .map(|prefix| prefix.try_into().expect("slice is 4 bytes"))
 }
 
 /// A trait for router's creation.
-trait CreateRouter: Send + Sync {
+trait CreateRouter {
     /// Instantiate a new router.
     fn create(&self) -> Box<dyn Router>;
 }
@@ -109,15 +264,6 @@ struct RouterFactory<R> {
     phantom: PhantomData<R>,
 }
 
-// SAFETY: We used `PhantomData` and lied to rust compiler that
-// [`RouterFactory`] contains type `R`.
-// In fact, it is a void type that contains neither other types nor references
-// and can be safely shared or sent between threads.
-// We will cheat rust the second time and explicitly implement `Send` and `Sync`
-// for [`RouterFactory`].
-unsafe impl<R> Send for RouterFactory<R> {}
-unsafe impl<R> Sync for RouterFactory<R> {}
-
 impl<R: StorageType + Router + 'static> CreateRouter for RouterFactory<R> {
     fn create(&self) -> Box<dyn Router> {
         Box::new(create_default_storage_type::<R>())
@@ -147,3 +293,129 @@ where
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECTOR: [u8; 4] = [0xAA, 0xBB, 0xCC, 0xDD];
+    const OTHER_SELECTOR: [u8; 4] = [0x11, 0x22, 0x33, 0x44];
+
+    fn calldata_with_selector(selector: [u8; 4]) -> Vec<u8> {
+        selector.to_vec()
+    }
+
+    #[test]
+    fn mock_call_short_circuits_route() {
+        let addr = Address::from([10u8; 20]);
+
+        VMRouter::new(addr).mock_call(SELECTOR, |_calldata| Ok(vec![0x42]));
+
+        let result =
+            VMRouter::new(addr).route(calldata_with_selector(SELECTOR));
+
+        assert_eq!(result, Ok(vec![0x42]));
+
+        VMRouter::new(addr).reset_storage();
+    }
+
+    #[test]
+    #[should_panic(expected = "is not initialized")]
+    fn non_matching_selector_falls_through_to_real_router() {
+        let addr = Address::from([11u8; 20]);
+
+        VMRouter::new(addr).mock_call(SELECTOR, |_calldata| Ok(vec![]));
+
+        // No real router is registered for this address, so a selector
+        // that doesn't match the stub falls through to the real-router
+        // path in `route` and panics the same way a genuinely
+        // uninitialized contract would.
+        let _ =
+            VMRouter::new(addr).route(calldata_with_selector(OTHER_SELECTOR));
+    }
+
+    #[test]
+    fn mock_revert_returns_err_with_data() {
+        let addr = Address::from([12u8; 20]);
+
+        VMRouter::new(addr).mock_revert(SELECTOR, vec![0xDE, 0xAD]);
+
+        let result =
+            VMRouter::new(addr).route(calldata_with_selector(SELECTOR));
+
+        assert_eq!(result, Err(vec![0xDE, 0xAD]));
+
+        VMRouter::new(addr).reset_storage();
+    }
+
+    #[test]
+    fn nested_route_calls_populate_call_stack_in_order() {
+        let outer = Address::from([1u8; 20]);
+        let inner = Address::from([2u8; 20]);
+
+        VMRouter::new(inner).mock_call(SELECTOR, move |_calldata| {
+            assert_eq!(
+                VMRouter::current_call_stack(),
+                vec![outer, inner]
+            );
+            assert_eq!(VMRouter::call_depth(), 2);
+            Ok(vec![])
+        });
+        VMRouter::new(outer).mock_call(SELECTOR, move |calldata| {
+            assert_eq!(VMRouter::current_call_stack(), vec![outer]);
+            assert_eq!(VMRouter::call_depth(), 1);
+            VMRouter::new(inner).route(calldata)
+        });
+
+        let result =
+            VMRouter::new(outer).route(calldata_with_selector(SELECTOR));
+
+        assert_eq!(result, Ok(vec![]));
+        assert_eq!(VMRouter::call_depth(), 0);
+        assert!(VMRouter::current_call_stack().is_empty());
+
+        VMRouter::new(outer).reset_storage();
+        VMRouter::new(inner).reset_storage();
+    }
+
+    #[test]
+    fn exceeding_max_call_depth_returns_err_instead_of_recursing() {
+        let addr = Address::from([3u8; 20]);
+
+        VMRouter::new(addr).mock_call(SELECTOR, move |calldata| {
+            VMRouter::new(addr).route(calldata)
+        });
+        VMRouter::set_max_call_depth(3);
+
+        let result =
+            VMRouter::new(addr).route(calldata_with_selector(SELECTOR));
+
+        assert!(result.is_err());
+        assert_eq!(VMRouter::call_depth(), 0);
+
+        VMRouter::new(addr).reset_storage();
+    }
+
+    #[test]
+    fn current_call_stack_detects_reentrant_cycle() {
+        let addr = Address::from([4u8; 20]);
+
+        VMRouter::new(addr).mock_call(SELECTOR, move |calldata| {
+            let occurrences = VMRouter::current_call_stack()
+                .iter()
+                .filter(|&&a| a == addr)
+                .count();
+            if occurrences > 1 {
+                return Err(b"reentrant call detected".to_vec());
+            }
+            VMRouter::new(addr).route(calldata)
+        });
+
+        let result =
+            VMRouter::new(addr).route(calldata_with_selector(SELECTOR));
+
+        assert_eq!(result, Err(b"reentrant call detected".to_vec()));
+
+        VMRouter::new(addr).reset_storage();
+    }
+}